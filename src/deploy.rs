@@ -1,9 +1,29 @@
-use std::path::{Path, PathBuf};
+use std::{
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use crate::{parse_int, preset::UF2_PRESETS};
+
+// Known Board-ID / Model substrings mapped to a UF2 family short name, checked
+// most-specific first. Lets the family validation fire for the common boards
+// whose INFO_UF2.TXT carries no explicit family line.
+const BOARD_FAMILY_HINTS: &[(&str, &str)] = &[
+    ("rpi-rp2350", "rp2350"),
+    ("rp2350", "rp2350"),
+    ("rpi-rp2", "rp2040"),
+    ("rp2040", "rp2040"),
+    ("nrf52840", "nrf52840"),
+    ("nrf52833", "nrf52833"),
+    ("nrf52", "nrf52"),
+];
 
 pub fn deploy_uf2(
     deploy_path_args: String,
     uf2_path: PathBuf,
     deploy_retry_count: u32,
+    family_id: u32,
+    force: bool,
 ) -> anyhow::Result<()> {
     let bar = indicatif::ProgressBar::new(0)
         .with_style(
@@ -26,9 +46,17 @@ pub fn deploy_uf2(
                 deploy_retry_count
             ));
 
-            let Ok(deploy_path) = get_uf2_deploy_path(deploy_path_args.clone(), &uf2_path) else {
-                continue;
-            };
+            let deploy_path =
+                match get_uf2_deploy_path(&deploy_path_args, &uf2_path, family_id, force) {
+                    // No suitable volume mounted yet; keep retrying.
+                    Ok(None) => continue,
+                    Ok(Some(deploy_path)) => deploy_path,
+                    // A volume was found but failed validation or selection: this is fatal.
+                    Err(e) => {
+                        bar.abandon_with_message("Aborted");
+                        return Err(e);
+                    }
+                };
 
             match fs_extra::file::copy_with_progress(
                 &uf2_path,
@@ -57,25 +85,179 @@ pub fn deploy_uf2(
     Ok(())
 }
 
-fn get_uf2_deploy_path(deploy_path: String, uf2_path: &Path) -> anyhow::Result<PathBuf> {
-    let deploy_dir = if deploy_path == "auto" {
-        // search mount that have "INFO_UF2.txt" file
+// Resolve the destination path for the UF2 file.
+//
+// Returns `Ok(None)` when auto-detection has not found a UF2 volume yet (so the
+// caller should keep retrying), `Ok(Some(path))` for the destination (an
+// explicit path is used as given; an auto-detected volume is checked against
+// the family first), and `Err` for a fatal error that retrying cannot fix: a
+// family mismatch or an invalid volume selection.
+fn get_uf2_deploy_path(
+    deploy_path: &str,
+    uf2_path: &Path,
+    family_id: u32,
+    force: bool,
+) -> anyhow::Result<Option<PathBuf>> {
+    let file_name = uf2_path.file_name().unwrap();
+
+    if deploy_path != "auto" {
+        return Ok(Some(PathBuf::from(deploy_path).join(file_name)));
+    }
+
+    // Collect every mounted volume that carries an INFO_UF2.TXT.
+    let mut volumes = Vec::new();
+    for disk in sysinfo::Disks::new_with_refreshed_list().iter() {
+        let path = disk.mount_point().to_path_buf();
+        let info_path = path.join("INFO_UF2.TXT");
+        if info_path.exists() {
+            let info = std::fs::read_to_string(&info_path)
+                .map(|contents| Uf2Info::parse(&contents))
+                .unwrap_or_default();
+            volumes.push((path, info));
+        }
+    }
+
+    if volumes.is_empty() {
+        return Ok(None);
+    }
+
+    let (mount, info) = if volumes.len() == 1 {
+        volumes.into_iter().next().unwrap()
+    } else {
+        pick_volume(volumes)?
+    };
 
-        let mut deploy_dir = None;
-        for disk in sysinfo::Disks::new_with_refreshed_list().iter() {
-            let path = disk.mount_point().to_path_buf();
-            if path.join("INFO_UF2.TXT").exists() {
-                deploy_dir = Some(path);
-                break;
+    validate_family(&info, family_id, force)?;
+
+    Ok(Some(mount.join(file_name)))
+}
+
+// Parsed contents of an INFO_UF2.TXT file.
+#[derive(Debug, Default)]
+struct Uf2Info {
+    model: Option<String>,
+    board_id: Option<String>,
+    // The family declared by the volume, if any (e.g. a `Family:` line).
+    family_hint: Option<String>,
+}
+
+impl Uf2Info {
+    fn parse(contents: &str) -> Self {
+        let mut info = Uf2Info::default();
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim().to_string();
+            match key.as_str() {
+                "model" => info.model = Some(value),
+                "board-id" => info.board_id = Some(value),
+                k if k.contains("family") => info.family_hint = Some(value),
+                _ => {}
             }
         }
-        if let Some(deploy_dir) = deploy_dir {
-            deploy_dir
-        } else {
-            anyhow::bail!("No mount found that have INFO_UF2.TXT file");
+        info
+    }
+
+    // Resolve the declared family to a UF2 family id. Canonical bootloader
+    // volumes rarely carry an explicit family line, so we fall back to
+    // recognising the hardware from its Board-ID / Model.
+    fn declared_family(&self) -> Option<u32> {
+        if let Some(hint) = &self.family_hint {
+            if let Some(id) = UF2_PRESETS
+                .get(&hint.to_lowercase())
+                .map(|preset| preset.id)
+                .or_else(|| parse_int(hint).ok())
+            {
+                return Some(id);
+            }
+        }
+
+        let haystack = format!(
+            "{} {}",
+            self.board_id.clone().unwrap_or_default(),
+            self.model.clone().unwrap_or_default()
+        )
+        .to_lowercase();
+        for (pattern, family) in BOARD_FAMILY_HINTS {
+            if haystack.contains(pattern) {
+                if let Some(preset) = UF2_PRESETS.get(*family) {
+                    return Some(preset.id);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn label(&self) -> String {
+        match (&self.board_id, &self.model) {
+            (Some(board), _) => board.clone(),
+            (None, Some(model)) => model.clone(),
+            (None, None) => "<unknown board>".to_string(),
+        }
+    }
+}
+
+// Abort when the volume declares a family that differs from the one being
+// deployed, unless the user passed `--force`.
+fn validate_family(info: &Uf2Info, family_id: u32, force: bool) -> anyhow::Result<()> {
+    let expected = UF2_PRESETS
+        .iter()
+        .find(|(_, v)| v.id == family_id)
+        .map(|(k, _)| k.clone());
+
+    if let Some(declared) = info.declared_family() {
+        if declared != family_id {
+            if force {
+                eprintln!(
+                    "WARN: Volume declares family 0x{:08x} but deploying 0x{:08x}. Continuing due to --force.",
+                    declared, family_id
+                );
+            } else {
+                anyhow::bail!(
+                    "Board family mismatch: volume '{}' declares family 0x{:08x}, but the UF2 targets 0x{:08x}{}. Use --force to override.",
+                    info.label(),
+                    declared,
+                    family_id,
+                    expected
+                        .map(|n| format!(" ({})", n))
+                        .unwrap_or_default()
+                );
+            }
         }
     } else {
-        PathBuf::from(deploy_path)
-    };
-    Ok(deploy_dir.join(uf2_path.file_name().unwrap()))
+        // The volume does not declare a family, so we can only report what we
+        // found and let the deploy proceed.
+        eprintln!(
+            "Deploying to '{}' (family could not be verified from INFO_UF2.TXT).",
+            info.label()
+        );
+    }
+
+    Ok(())
+}
+
+// Prompt the user to choose between multiple mounted UF2 volumes.
+fn pick_volume(volumes: Vec<(PathBuf, Uf2Info)>) -> anyhow::Result<(PathBuf, Uf2Info)> {
+    eprintln!("Multiple UF2 volumes detected:");
+    for (i, (mount, info)) in volumes.iter().enumerate() {
+        eprintln!("  [{}] {} ({})", i + 1, info.label(), mount.display());
+    }
+    eprint!("Select a volume [1-{}]: ", volumes.len());
+    std::io::stderr().flush()?;
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    let index: usize = input
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid selection: '{}'", input.trim()))?;
+
+    if index == 0 || index > volumes.len() {
+        anyhow::bail!("Selection {} is out of range", index);
+    }
+
+    Ok(volumes.into_iter().nth(index - 1).unwrap())
 }
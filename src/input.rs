@@ -0,0 +1,244 @@
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::Path,
+};
+
+use anyhow::{Context, bail};
+use goblin::{
+    Object,
+    elf::program_header::PT_LOAD,
+};
+
+/// Parsed firmware image: a base address plus the loadable segments as
+/// (absolute load address, bytes) pairs. Every input format is decoded into
+/// this shape before it reaches the UF2 writer.
+pub struct Firmware {
+    pub base_addr: u32,
+    pub segments: Vec<(u32, Vec<u8>)>,
+}
+
+impl Firmware {
+    /// The lowest load address across all segments.
+    pub fn min_addr(&self) -> u32 {
+        self.segments.iter().map(|(a, _)| *a).min().unwrap_or(0)
+    }
+}
+
+/// An input binary format that can be decoded into a [`Firmware`].
+///
+/// `base_addr` overrides the format's own notion of the base address when
+/// given; raw binaries require it since they carry no address information.
+pub trait FromReader {
+    fn from_reader(reader: impl Read, base_addr: Option<u32>) -> anyhow::Result<Firmware>;
+}
+
+/// Detect the input format from the file extension, falling back to sniffing
+/// the first bytes of the content, and decode it.
+pub fn read_firmware(path: &Path, base_addr: Option<u32>) -> anyhow::Result<Firmware> {
+    let data = std::fs::read(path)?;
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("hex") => IntelHex::from_reader(&data[..], base_addr),
+        Some("bin") => RawBin::from_reader(&data[..], base_addr),
+        Some("elf") => Elf::from_reader(&data[..], base_addr),
+        _ => {
+            // Sniff by content: ELF magic, or a leading ':' for Intel HEX.
+            if data.starts_with(&[0x7f, b'E', b'L', b'F']) {
+                Elf::from_reader(&data[..], base_addr)
+            } else if data.first() == Some(&b':') {
+                IntelHex::from_reader(&data[..], base_addr)
+            } else {
+                RawBin::from_reader(&data[..], base_addr)
+            }
+        }
+    }
+}
+
+/// ELF input, decoded via goblin.
+pub struct Elf;
+
+impl FromReader for Elf {
+    fn from_reader(mut reader: impl Read, base_addr: Option<u32>) -> anyhow::Result<Firmware> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let elf = match Object::parse(&data) {
+            Ok(Object::Elf(elf)) => elf,
+            Ok(_) => bail!("The input file is not an ELF file."),
+            Err(e) => bail!("Failed to parse ELF file: {}", e),
+        };
+
+        let mut segments = Vec::new();
+        for phdr in elf
+            .program_headers
+            .iter()
+            .filter(|phdr| phdr.p_type == PT_LOAD && phdr.p_filesz > 0)
+        {
+            let file_offset = phdr.p_offset as usize;
+            let read_size = phdr.p_filesz as usize;
+            if file_offset
+                .checked_add(read_size)
+                .is_none_or(|end| end > data.len())
+            {
+                bail!(
+                    "Segment data range (offset=0x{:x}, filesz=0x{:x}) exceeds ELF file size ({} bytes).",
+                    phdr.p_offset,
+                    phdr.p_filesz,
+                    data.len()
+                );
+            }
+            segments.push((
+                phdr.p_paddr as u32,
+                data[file_offset..file_offset + read_size].to_vec(),
+            ));
+        }
+
+        if segments.is_empty() {
+            bail!("No valid PT_LOAD segments with p_filesz > 0 found in the ELF file.");
+        }
+
+        // base_addr is the minimum virtual address of PT_LOAD segments, unless
+        // overridden by the caller.
+        let base_addr = base_addr.unwrap_or_else(|| {
+            elf.program_headers
+                .iter()
+                .filter(|ph| ph.p_type == PT_LOAD)
+                .map(|ph| ph.p_vaddr as u32)
+                .min()
+                .unwrap_or_else(|| {
+                    eprintln!("WARN: No PT_LOAD segment found in ELF. Using 0 as base address.");
+                    0
+                })
+        });
+
+        Ok(Firmware { base_addr, segments })
+    }
+}
+
+/// Intel HEX input.
+pub struct IntelHex;
+
+impl FromReader for IntelHex {
+    fn from_reader(mut reader: impl Read, base_addr: Option<u32>) -> anyhow::Result<Firmware> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let text = std::str::from_utf8(&data).context("Intel HEX file is not valid UTF-8")?;
+
+        let mut map: BTreeMap<u32, u8> = BTreeMap::new();
+        // Upper address bits contributed by extended address records.
+        let mut ext_base: u32 = 0;
+
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let body = line
+                .strip_prefix(':')
+                .with_context(|| format!("Line {} does not start with ':'", lineno + 1))?;
+            let bytes = decode_hex(body)
+                .with_context(|| format!("Line {} is not valid hex", lineno + 1))?;
+            if bytes.len() < 5 {
+                bail!("Line {} is too short for an Intel HEX record", lineno + 1);
+            }
+
+            let count = bytes[0] as usize;
+            let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let rtype = bytes[3];
+            if bytes.len() != count + 5 {
+                bail!("Line {} byte count does not match record length", lineno + 1);
+            }
+            if bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)) != 0 {
+                bail!("Line {} has an invalid checksum", lineno + 1);
+            }
+            let payload = &bytes[4..4 + count];
+
+            match rtype {
+                0x00 => {
+                    for (i, b) in payload.iter().enumerate() {
+                        map.insert(ext_base + addr + i as u32, *b);
+                    }
+                }
+                0x01 => break,
+                0x02 | 0x04 => {
+                    if count != 2 {
+                        bail!(
+                            "Line {} extended address record must carry exactly 2 data bytes, got {}",
+                            lineno + 1,
+                            count
+                        );
+                    }
+                    let value = u16::from_be_bytes([payload[0], payload[1]]) as u32;
+                    ext_base = if rtype == 0x02 { value << 4 } else { value << 16 };
+                }
+                // Start-address records carry no data to place.
+                _ => {}
+            }
+        }
+
+        if map.is_empty() {
+            bail!("Intel HEX file contains no data records");
+        }
+
+        let segments = coalesce(map);
+        let base_addr = base_addr.unwrap_or_else(|| segments.iter().map(|(a, _)| *a).min().unwrap());
+
+        Ok(Firmware { base_addr, segments })
+    }
+}
+
+/// Raw binary input. Carries no address information, so `base_addr` is required.
+pub struct RawBin;
+
+impl FromReader for RawBin {
+    fn from_reader(mut reader: impl Read, base_addr: Option<u32>) -> anyhow::Result<Firmware> {
+        let base_addr =
+            base_addr.context("Raw binary input requires --base-addr to be specified")?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if data.is_empty() {
+            bail!("Raw binary input is empty");
+        }
+        Ok(Firmware {
+            base_addr,
+            segments: vec![(base_addr, data)],
+        })
+    }
+}
+
+// Merge a sorted address->byte map into contiguous (start, bytes) runs.
+fn coalesce(map: BTreeMap<u32, u8>) -> Vec<(u32, Vec<u8>)> {
+    let mut segments: Vec<(u32, Vec<u8>)> = Vec::new();
+    let mut cur: Option<(u32, Vec<u8>)> = None;
+    for (addr, byte) in map {
+        if let Some((start, buf)) = cur.as_mut() {
+            if *start + buf.len() as u32 == addr {
+                buf.push(byte);
+                continue;
+            }
+        }
+        if let Some(seg) = cur.take() {
+            segments.push(seg);
+        }
+        cur = Some((addr, vec![byte]));
+    }
+    if let Some(seg) = cur.take() {
+        segments.push(seg);
+    }
+    segments
+}
+
+// Decode a string of ASCII hex digit pairs into bytes.
+fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        bail!("hex string has an odd number of digits");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(Into::into))
+        .collect()
+}
@@ -7,6 +7,7 @@ use preset::UF2_PRESETS;
 use std::path::PathBuf;
 
 mod deploy;
+mod input;
 mod preset;
 mod uf2;
 
@@ -26,10 +27,10 @@ pub enum Commands {
         #[arg(long, short)]
         family: String,
 
-        /// Base address of the binary. Usually you don't need to specify this as this is
-        /// automatically read from the ELF file.
-        ///
-        /// By specifying this, you can override the base address.
+        /// Base address of the binary. For ELF and Intel HEX inputs this is
+        /// derived automatically, and specifying it overrides the detected
+        /// value. Raw bin inputs carry no address information, so this is
+        /// required for them.
         #[arg(long, short)]
         base_addr: Option<String>,
 
@@ -43,8 +44,44 @@ pub enum Commands {
         #[arg(long, default_value_t = 40)]
         deploy_retry_count: u32,
 
-        /// Path of elf file. Usually passed by `cargo run`.
-        elf_path: String,
+        /// Deploy even when the target volume's declared board family does not
+        /// match the UF2 being deployed.
+        #[arg(long)]
+        force: bool,
+
+        /// Firmware version string to embed as a UF2 extension tag.
+        #[arg(long)]
+        fw_version: Option<String>,
+
+        /// Device-type id (a 32-bit number) to embed as a UF2 extension tag.
+        #[arg(long)]
+        device_type: Option<String>,
+
+        /// Semantic version string to embed as a UF2 extension tag.
+        #[arg(long)]
+        semver: Option<String>,
+
+        /// Embed the ELF `.note.gnu.build-id` as a UF2 extension tag.
+        #[arg(long)]
+        embed_build_id: bool,
+
+        /// Emit one UF2 block stream per PT_LOAD segment instead of a single
+        /// contiguous image, skipping the gaps between segments.
+        #[arg(long)]
+        sparse: bool,
+
+        /// Path of the input file (ELF, Intel HEX, or raw bin). Usually passed
+        /// by `cargo run` for ELF inputs.
+        input_path: String,
+    },
+    /// Decode a UF2 file back to a binary and print its structure.
+    Dump {
+        /// Path to the UF2 file to inspect.
+        uf2_path: PathBuf,
+
+        /// If specified, write the reassembled binary image to this path.
+        #[arg(long, short)]
+        out_bin: Option<PathBuf>,
     },
     /// Show available UF2 families.
     ListFamilies,
@@ -59,7 +96,13 @@ pub fn main() -> anyhow::Result<()> {
             base_addr,
             path,
             deploy_retry_count,
-            elf_path,
+            force,
+            fw_version,
+            device_type,
+            semver,
+            embed_build_id,
+            sparse,
+            input_path,
         } => {
             let family = if let Some(preset) = UF2_PRESETS.get(&family) {
                 preset.id
@@ -68,22 +111,95 @@ pub fn main() -> anyhow::Result<()> {
             };
             let base_addr = base_addr.map(|s| parse_int(&s)).transpose()?;
 
-            eprintln!(
-                "ELF file is generated at: {} ({})",
-                elf_path,
-                get_bytes(&elf_path)
-            );
+            eprintln!("Input file: {} ({})", input_path, get_bytes(&input_path));
+
+            let input_path = dunce::canonicalize(PathBuf::from(input_path))?;
 
-            let elf_path = dunce::canonicalize(PathBuf::from(elf_path))?;
+            let build_id = if embed_build_id {
+                let id = uf2::read_build_id(&input_path)?;
+                if id.is_none() {
+                    eprintln!("WARN: No .note.gnu.build-id found in ELF. Skipping build-id tag.");
+                }
+                id
+            } else {
+                None
+            };
+            let meta = uf2::Uf2Metadata {
+                version: fw_version,
+                device_type,
+                semver,
+                build_id,
+            };
 
-            let uf2_path = uf2::elf2uf2(&elf_path, family, base_addr)?;
+            let uf2_path = uf2::build_uf2(&input_path, family, base_addr, sparse, meta)?;
 
             if let Some(deploy_path) = path {
-                deploy::deploy_uf2(deploy_path, uf2_path, deploy_retry_count)?;
+                deploy::deploy_uf2(deploy_path, uf2_path, deploy_retry_count, family, force)?;
             } else {
                 eprintln!("Path is not specified. Skipping deploy.",);
             }
         }
+        Commands::Dump { uf2_path, out_bin } => {
+            let data = std::fs::read(&uf2_path)?;
+            let dump = uf2::dump_uf2(&data)?;
+
+            let family_name = UF2_PRESETS
+                .iter()
+                .find(|(_, v)| v.id == dump.family_id)
+                .map(|(k, _)| k.clone())
+                .unwrap_or_else(|| "unknown".to_string());
+
+            let gaps = if dump.gaps.is_empty() {
+                "none".to_string()
+            } else {
+                dump.gaps
+                    .iter()
+                    .map(|(addr, len)| format!("0x{:08x} (+{})", addr, len))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let tags = if dump.tags.is_empty() {
+                "none".to_string()
+            } else {
+                dump.tags
+                    .iter()
+                    .map(|t| format!("{}={}", t.type_name(), t.display_value()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+
+            let table = vec![
+                vec![
+                    "Family".cell(),
+                    format!("{} (0x{:08x})", family_name, dump.family_id).cell(),
+                ],
+                vec![
+                    "Address range".cell(),
+                    format!("0x{:08x}..0x{:08x}", dump.start_addr, dump.end_addr).cell(),
+                ],
+                vec![
+                    "Blocks".cell(),
+                    format!("{} (declared {})", dump.blocks_seen, dump.num_blocks).cell(),
+                ],
+                vec!["Gaps".cell(), gaps.cell()],
+                vec!["Extension tags".cell(), tags.cell()],
+            ]
+            .table()
+            .separator(
+                Separator::builder()
+                    .row(None)
+                    .column(Some(VerticalLine::default()))
+                    .title(Some(HorizontalLine::default()))
+                    .build(),
+            );
+
+            cli_table::print_stdout(table)?;
+
+            if let Some(out_bin) = out_bin {
+                std::fs::write(&out_bin, &dump.image)?;
+                eprintln!("Reassembled binary written to {}", out_bin.display());
+            }
+        }
         Commands::ListFamilies => {
             let table = UF2_PRESETS
                 .iter()
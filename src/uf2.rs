@@ -1,51 +1,145 @@
 use std::{
-    cmp::{max, min},
+    cmp::min,
     fs::File,
-    io::{Read as _, Write as _},
+    io::Read as _,
     path::{Path, PathBuf},
 };
 
 use anyhow::{Context, bail};
-use goblin::{
-    Object,
-    elf::{Elf, ProgramHeader, program_header::PT_LOAD},
-};
+use goblin::elf::{Elf, note::NT_GNU_BUILD_ID};
+
+use crate::input::{self, Firmware};
+use crate::{get_bytes, parse_int};
+
+// UF2 block constants. See https://github.com/microsoft/uf2 for the format.
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+
+const UF2_FLAG_FAMILY_ID: u32 = 0x0000_2000;
+const UF2_FLAG_EXTENSION_TAGS: u32 = 0x0000_8000;
+
+// Each block carries at most 256 bytes of payload out of the 476-byte data region.
+const UF2_PAYLOAD_SIZE: usize = 256;
+const UF2_DATA_SIZE: usize = 476;
+
+// Known extension tag types (upper 3 bytes of the tag header).
+const TAG_FIRMWARE_VERSION: u32 = 0x9f_c7bc;
+const TAG_DEVICE_TYPE: u32 = 0x65_0d9d;
+const TAG_SEMVER: u32 = 0xb3_db8c;
+// Tool-specific tag carrying the hex-encoded ELF build-id. Kept distinct from
+// the firmware-version tag so the two can coexist unambiguously.
+const TAG_BUILD_ID: u32 = 0x2b_9d6e;
 
-use crate::get_bytes;
+/// Optional provenance metadata embedded into the first UF2 block as extension tags.
+#[derive(Debug, Default)]
+pub struct Uf2Metadata {
+    /// Firmware version string (`0x9fc7bc`).
+    pub version: Option<String>,
+    /// Device-type id (`0x650d9d`).
+    pub device_type: Option<String>,
+    /// Semantic version string (`0xb3db8c`).
+    pub semver: Option<String>,
+    /// Hex-encoded ELF build-id (`0x2b9d6e`).
+    pub build_id: Option<String>,
+}
+
+impl Uf2Metadata {
+    fn is_empty(&self) -> bool {
+        self.version.is_none()
+            && self.device_type.is_none()
+            && self.semver.is_none()
+            && self.build_id.is_none()
+    }
 
-pub fn elf2uf2(
-    elf_path: &std::path::Path,
+    // Collect the configured metadata into (tag type, value bytes) records, in a
+    // stable order so the output is reproducible. The device-type tag holds a
+    // 32-bit numeric id, so it is parsed and emitted as 4 little-endian bytes.
+    fn tags(&self) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+        let mut tags = Vec::new();
+        if let Some(build_id) = &self.build_id {
+            tags.push((TAG_BUILD_ID, build_id.clone().into_bytes()));
+        }
+        if let Some(version) = &self.version {
+            tags.push((TAG_FIRMWARE_VERSION, version.clone().into_bytes()));
+        }
+        if let Some(device_type) = &self.device_type {
+            let id = parse_int(device_type)
+                .with_context(|| format!("Invalid device-type id '{}'", device_type))?;
+            tags.push((TAG_DEVICE_TYPE, id.to_le_bytes().to_vec()));
+        }
+        if let Some(semver) = &self.semver {
+            tags.push((TAG_SEMVER, semver.clone().into_bytes()));
+        }
+        Ok(tags)
+    }
+
+    // Encode the tag records as laid out at the tail of a block's data region.
+    // Each record is a little-endian u32 header (low byte = total size excluding
+    // padding, per the UF2 spec, upper 3 bytes = type) followed by the value.
+    // Records are padded to a 4-byte boundary, but the padding is not counted in
+    // the size byte so standard readers recover the exact value.
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        for (ty, value) in self.tags()? {
+            let total = 4 + value.len();
+            if total > 0xff {
+                bail!(
+                    "Extension tag value is too large to encode ({} bytes)",
+                    value.len()
+                );
+            }
+            let header = (ty << 8) | total as u32;
+            buf.extend_from_slice(&header.to_le_bytes());
+            buf.extend_from_slice(&value);
+            buf.resize(buf.len() + (total.next_multiple_of(4) - total), 0);
+        }
+        Ok(buf)
+    }
+}
+
+pub fn build_uf2(
+    input_path: &std::path::Path,
     family_id: u32,
     base_addr: Option<u32>,
+    sparse: bool,
+    meta: Uf2Metadata,
 ) -> anyhow::Result<PathBuf> {
-    let base_addr = if let Some(base_addr) = base_addr {
-        base_addr
-    } else {
-        get_base_addr_of_elf(elf_path)?
-    };
+    let firmware = input::read_firmware(input_path, base_addr)?;
+    let base_addr = firmware.base_addr;
+    let min_addr = firmware.min_addr();
     eprintln!(
         "Generating UF2. Family: 0x{:08x}, Base Address: 0x{:08x}",
         family_id, base_addr
     );
 
-    let artifact_dir = elf_path.parent().context("No parent dir in output file")?;
-    let artifact_name = elf_path
+    let artifact_dir = input_path.parent().context("No parent dir in output file")?;
+    let artifact_name = input_path
         .file_stem()
         .context("No file stem in output file")?
         .to_string_lossy();
 
-    // elf to bin
-    let bin_path = artifact_dir.join(format!("{}.bin", artifact_name));
-    elf2bin(elf_path, &bin_path)?;
-    eprintln!(
-        "Bin file is generated at: {} ({})",
-        bin_path.display(),
-        get_bytes(&bin_path)
-    );
-
-    // bin to uf2
     let uf2_path = artifact_dir.join(format!("{}.uf2", artifact_name));
-    let uf2_data = uf2::bin_to_uf2(&std::fs::read(bin_path)?, family_id, base_addr)?;
+
+    let uf2_data = if sparse {
+        // Walk each segment independently instead of materializing one
+        // contiguous image, so gaps between regions are never zero-filled.
+        // Setting base_addr == min_addr yields absolute addressing (each block
+        // targets its segment's load address directly).
+        bin_to_uf2_sparse(&firmware.segments, family_id, base_addr, min_addr, &meta)?
+    } else {
+        // Flatten into one contiguous image and emit a companion .bin.
+        let flat = flatten(&firmware, min_addr);
+        let bin_path = artifact_dir.join(format!("{}.bin", artifact_name));
+        std::fs::write(&bin_path, &flat).context("Could not create bin file")?;
+        eprintln!(
+            "Bin file is generated at: {} ({})",
+            bin_path.display(),
+            get_bytes(&bin_path)
+        );
+        bin_to_uf2(&flat, family_id, base_addr, &meta)?
+    };
+
     std::fs::write(&uf2_path, uf2_data).context("Failed to write uf2 file")?;
     eprintln!(
         "Uf2 file is generated at: {} ({})",
@@ -56,127 +150,317 @@ pub fn elf2uf2(
     Ok(uf2_path)
 }
 
-// base_addr is the minimum virtual address of PT_LOAD segments.
-fn get_base_addr_of_elf(path: &Path) -> anyhow::Result<u32> {
-    let mut file = File::open(path)?;
+// Materialize the firmware segments into a single contiguous image spanning
+// from `min_addr` to the highest segment end, zero-filling the gaps.
+fn flatten(firmware: &Firmware, min_addr: u32) -> Vec<u8> {
+    let end = firmware
+        .segments
+        .iter()
+        .map(|(addr, data)| addr + data.len() as u32)
+        .max()
+        .unwrap_or(min_addr);
+    let mut buffer = vec![0u8; (end - min_addr) as usize];
+    for (addr, data) in &firmware.segments {
+        let offset = (addr - min_addr) as usize;
+        buffer[offset..offset + data.len()].copy_from_slice(data);
+    }
+    buffer
+}
+
+/// Read the hex-encoded `.note.gnu.build-id` note from an ELF, if present.
+pub fn read_build_id(elf_path: &Path) -> anyhow::Result<Option<String>> {
+    let mut file = File::open(elf_path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
 
-    let elf = Elf::parse(&buffer).expect("Failed to parse ELF");
+    let Ok(elf) = Elf::parse(&buffer) else {
+        // Not an ELF (e.g. a raw bin or hex input); there is no build-id to read.
+        return Ok(None);
+    };
 
-    let base_address = elf
-        .program_headers
-        .iter()
-        .filter(|ph| ph.p_type == goblin::elf::program_header::PT_LOAD)
-        .map(|ph| ph.p_vaddr)
-        .min()
-        .unwrap_or_else(|| {
-            eprintln!("WARN: No PT_LOAD segment found in ELF. Using 0 as base address.");
-            0
-        });
+    let Some(notes) = elf.iter_note_headers(&buffer) else {
+        return Ok(None);
+    };
+    for note in notes.flatten() {
+        if note.name == "GNU" && note.n_type == NT_GNU_BUILD_ID {
+            let hex = note.desc.iter().map(|b| format!("{:02x}", b)).collect();
+            return Ok(Some(hex));
+        }
+    }
+    Ok(None)
+}
 
-    Ok(base_address as u32)
+/// A decoded extension tag record (type, value bytes).
+#[derive(Debug)]
+pub struct Uf2Tag {
+    pub ty: u32,
+    pub value: Vec<u8>,
 }
 
-// Example elf header (by readelf)
-//
-// Program Headers:
-//   Type           Offset   VirtAddr   PhysAddr   FileSiz MemSiz  Flg Align
-//                           ↓ min vaddr is used when genearting uf2 (= base addr)
-//   LOAD           0x000114 0x00026000 0x00026000 0x00100 0x00100 R   0x4      ← start is located to 0
-//                                      ↑ min_lma (global offset)
-//   LOAD           0x000214 0x00026100 0x00026100 0x1edf0 0x1edf0 R E 0x4      ← start is located to p_addr - min_lma
-//   LOAD           0x01f008 0x00044ef0 0x00044ef0 0x02c4c 0x02c4c R   0x8      ← same as above
-//   LOAD           0x021c58 0x20033e10 0x00047b40 0x0001c 0x0001c RW  0x8      ← same as above
-//                                      ↑ p_addr+file_sz is max_lma_end
-//   LOAD           0x021c80 0x20033e30 0x20033e30 0x00000 0x0c1cc RW  0x8      ← Ignored because of p_filesz == 0
-fn elf2bin(elf_path: &Path, bin_path: &Path) -> anyhow::Result<()> {
-    let mut elf_file = File::open(elf_path)?;
-    let mut elf_data = Vec::new();
-    elf_file.read_to_end(&mut elf_data)?;
-
-    let elf = match Object::parse(&elf_data) {
-        Ok(Object::Elf(elf)) => elf,
-        Ok(_) => {
-            bail!("The input file is not an ELF file.");
-        }
-        Err(e) => {
-            bail!("Failed to parse ELF file: {}", e);
+impl Uf2Tag {
+    /// Human-readable name for the known tag types, falling back to the hex id.
+    pub fn type_name(&self) -> String {
+        match self.ty {
+            TAG_FIRMWARE_VERSION => "firmware version".to_string(),
+            TAG_DEVICE_TYPE => "device type".to_string(),
+            TAG_SEMVER => "semver".to_string(),
+            TAG_BUILD_ID => "build id".to_string(),
+            other => format!("0x{:06x}", other),
         }
+    }
+
+    /// Render the tag value: the device-type tag is a 4-byte LE u32, every
+    /// other known tag is a string.
+    pub fn display_value(&self) -> String {
+        if self.ty == TAG_DEVICE_TYPE && self.value.len() == 4 {
+            let id = u32::from_le_bytes([self.value[0], self.value[1], self.value[2], self.value[3]]);
+            format!("0x{:08x}", id)
+        } else {
+            String::from_utf8_lossy(&self.value).to_string()
+        }
+    }
+}
+
+/// The result of decoding a UF2 file back to its constituent parts.
+#[derive(Debug)]
+pub struct Uf2Dump {
+    pub family_id: u32,
+    pub start_addr: u32,
+    pub end_addr: u32,
+    pub num_blocks: u32,
+    pub blocks_seen: usize,
+    /// (gap start address, gap length) for holes between consecutive blocks.
+    pub gaps: Vec<(u32, u32)>,
+    pub tags: Vec<Uf2Tag>,
+    /// The reassembled image (gaps zero-filled) spanning `start_addr..end_addr`.
+    pub image: Vec<u8>,
+}
+
+/// Decode a UF2 file: validate every block's magics and reconstruct the image
+/// by placing each block's payload at its `targetAddr`.
+pub fn dump_uf2(data: &[u8]) -> anyhow::Result<Uf2Dump> {
+    if data.is_empty() || data.len() % 512 != 0 {
+        bail!(
+            "UF2 file size ({} bytes) is not a non-zero multiple of 512.",
+            data.len()
+        );
+    }
+
+    let read_u32 = |block: &[u8], off: usize| {
+        u32::from_le_bytes([block[off], block[off + 1], block[off + 2], block[off + 3]])
     };
 
-    // Extract PT_LOAD segments with file size > 0
-    let loadable_segments: Vec<&ProgramHeader> = elf
-        .program_headers
-        .iter()
-        .filter(|phdr| phdr.p_type == PT_LOAD && phdr.p_filesz > 0)
-        .collect();
+    let mut family_id = 0u32;
+    let mut num_blocks = 0u32;
+    let mut tags = Vec::new();
+    // (targetAddr, payload) of every block, for reassembly and gap detection.
+    let mut chunks: Vec<(u32, Vec<u8>)> = Vec::new();
+
+    for (i, block) in data.chunks_exact(512).enumerate() {
+        if read_u32(block, 0) != UF2_MAGIC_START0
+            || read_u32(block, 4) != UF2_MAGIC_START1
+            || read_u32(block, 508) != UF2_MAGIC_END
+        {
+            bail!("Block {} has invalid UF2 magics.", i);
+        }
+
+        let flags = read_u32(block, 8);
+        let target_addr = read_u32(block, 12);
+        let payload_size = read_u32(block, 16) as usize;
+        let num = read_u32(block, 24);
+        let family = read_u32(block, 28);
+        let block_data = &block[32..32 + UF2_DATA_SIZE];
+
+        if payload_size > UF2_DATA_SIZE {
+            bail!("Block {} declares payload size {} > 476.", i, payload_size);
+        }
+
+        if flags & UF2_FLAG_FAMILY_ID != 0 {
+            family_id = family;
+        }
+        num_blocks = num;
 
-    if loadable_segments.is_empty() {
-        bail!("No valid PT_LOAD segments with p_filesz > 0 found in the ELF file.");
+        if flags & UF2_FLAG_EXTENSION_TAGS != 0 {
+            tags.extend(parse_tags(&block_data[payload_size..]));
+        }
+
+        chunks.push((target_addr, block_data[..payload_size].to_vec()));
     }
 
-    let mut min_lma = u64::MAX;
-    let mut max_lma_end = 0u64;
+    chunks.sort_by_key(|(addr, _)| *addr);
+    let start_addr = chunks.first().map(|(a, _)| *a).unwrap_or(0);
+    let end_addr = chunks
+        .iter()
+        .map(|(a, p)| a + p.len() as u32)
+        .max()
+        .unwrap_or(start_addr);
 
-    for phdr in &loadable_segments {
-        min_lma = min(min_lma, phdr.p_paddr);
-        max_lma_end = max(max_lma_end, phdr.p_paddr.saturating_add(phdr.p_filesz));
+    // Detect gaps between the end of one block and the start of the next.
+    let mut gaps = Vec::new();
+    let mut cursor = start_addr;
+    for (addr, payload) in &chunks {
+        if *addr > cursor {
+            gaps.push((cursor, addr - cursor));
+        }
+        cursor = cursor.max(addr + payload.len() as u32);
     }
 
-    if min_lma == u64::MAX {
-        bail!("Could not determine valid LMA range",);
+    let mut image = vec![0u8; (end_addr - start_addr) as usize];
+    for (addr, payload) in &chunks {
+        let offset = (addr - start_addr) as usize;
+        image[offset..offset + payload.len()].copy_from_slice(payload);
     }
 
-    let output_size = if max_lma_end > min_lma {
-        (max_lma_end - min_lma) as usize
-    } else {
-        bail!("Calculated output size based on LMA is zero. Output file will be empty.",);
-    };
+    Ok(Uf2Dump {
+        family_id,
+        start_addr,
+        end_addr,
+        num_blocks,
+        blocks_seen: chunks.len(),
+        gaps,
+        tags,
+        image,
+    })
+}
 
-    let mut output_buffer = vec![0u8; output_size];
-    // Copy segment data into the buffer based on LMA
-    for phdr in &loadable_segments {
-        // p_filesz > 0 is guaranteed by the filter.
-        let read_size = phdr.p_filesz as usize;
-        let file_offset = phdr.p_offset as usize;
-        // The starting position in the buffer is the relative offset from the overall minimum LMA.
-        let buffer_offset = (phdr.p_paddr - min_lma) as usize;
-
-        // Check if the segment data range (in the ELF file) is valid.
-        if file_offset
-            .checked_add(read_size)
-            .is_none_or(|end| end > elf_data.len())
-        {
-            bail!(
-                "Segment data range (offset=0x{:x}, filesz=0x{:x}) exceeds ELF file size ({} bytes).",
-                phdr.p_offset,
-                phdr.p_filesz,
-                elf_data.len()
-            );
+// Parse extension tag records from the tail of a block's data region. Records
+// continue until a zero header or the region is exhausted.
+fn parse_tags(mut region: &[u8]) -> Vec<Uf2Tag> {
+    let mut tags = Vec::new();
+    while region.len() >= 4 {
+        let header = u32::from_le_bytes([region[0], region[1], region[2], region[3]]);
+        // The size byte is the unpadded total; records are 4-byte aligned, so
+        // the value is exact and the next record starts at the padded offset.
+        let size = (header & 0xff) as usize;
+        let padded = size.next_multiple_of(4);
+        if size < 4 || padded > region.len() {
+            break;
         }
+        tags.push(Uf2Tag {
+            ty: header >> 8,
+            value: region[4..size].to_vec(),
+        });
+        region = &region[padded..];
+    }
+    tags
+}
 
-        if buffer_offset
-            .checked_add(read_size)
-            .is_none_or(|end| end > output_buffer.len())
-        {
-            bail!(
-                "Segment write range (LMA=0x{:x}, FileSz=0x{:x}, buffer_offset={}) exceeds output buffer size ({} bytes). min_lma=0x{:x}, max_lma_end=0x{:x}",
-                phdr.p_paddr,
-                phdr.p_filesz,
-                buffer_offset,
-                output_buffer.len(),
-                min_lma,
-                max_lma_end
-            );
-        }
-
-        let data_to_copy = &elf_data[file_offset..file_offset + read_size];
-        output_buffer[buffer_offset..buffer_offset + read_size].copy_from_slice(data_to_copy);
+// Serialize a single 512-byte UF2 block. `tags`, when non-empty, is appended to
+// the tail of the data region and the extension-tag flag is set.
+fn write_uf2_block(
+    out: &mut Vec<u8>,
+    target_addr: u32,
+    payload: &[u8],
+    block_no: u32,
+    num_blocks: u32,
+    family_id: u32,
+    tags: &[u8],
+) -> anyhow::Result<()> {
+    let mut flags = UF2_FLAG_FAMILY_ID;
+    let mut block_data = [0u8; UF2_DATA_SIZE];
+    block_data[..payload.len()].copy_from_slice(payload);
+
+    if !tags.is_empty() {
+        if payload.len() + tags.len() > UF2_DATA_SIZE {
+            bail!("Extension tags do not fit in the block's data region");
+        }
+        flags |= UF2_FLAG_EXTENSION_TAGS;
+        block_data[payload.len()..payload.len() + tags.len()].copy_from_slice(tags);
     }
 
-    let mut bin_file = File::create(bin_path).context("Could not create bin file")?;
-    bin_file.write_all(&output_buffer)?;
+    out.extend_from_slice(&UF2_MAGIC_START0.to_le_bytes());
+    out.extend_from_slice(&UF2_MAGIC_START1.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&target_addr.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&block_no.to_le_bytes());
+    out.extend_from_slice(&num_blocks.to_le_bytes());
+    out.extend_from_slice(&family_id.to_le_bytes());
+    out.extend_from_slice(&block_data);
+    out.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
 
     Ok(())
 }
+
+// Convert a flat binary into UF2 blocks targeting `base_addr`. When `meta`
+// carries any provenance, it is encoded as extension tags appended to the tail
+// of the first block's data region, and the block's extension-tag flag is set.
+fn bin_to_uf2(
+    data: &[u8],
+    family_id: u32,
+    base_addr: u32,
+    meta: &Uf2Metadata,
+) -> anyhow::Result<Vec<u8>> {
+    let num_blocks = data.len().div_ceil(UF2_PAYLOAD_SIZE).max(1) as u32;
+    let tags = if meta.is_empty() {
+        Vec::new()
+    } else {
+        meta.encode()?
+    };
+
+    let mut out = Vec::with_capacity(num_blocks as usize * 512);
+    for block_no in 0..num_blocks {
+        let start = block_no as usize * UF2_PAYLOAD_SIZE;
+        let end = min(start + UF2_PAYLOAD_SIZE, data.len());
+        let chunk = &data[start..end];
+        let tags = if block_no == 0 { tags.as_slice() } else { &[] };
+        write_uf2_block(
+            &mut out,
+            base_addr + start as u32,
+            chunk,
+            block_no,
+            num_blocks,
+            family_id,
+            tags,
+        )?;
+    }
+
+    Ok(out)
+}
+
+// Convert individual PT_LOAD segments into UF2 blocks without zero-filling the
+// gaps between them. Each segment is split into 256-byte payloads; blocks are
+// numbered sequentially and share one `numBlocks` total across all segments.
+// Each block targets `base_addr + (p_paddr - min_lma)`.
+fn bin_to_uf2_sparse(
+    segments: &[(u32, Vec<u8>)],
+    family_id: u32,
+    base_addr: u32,
+    min_lma: u32,
+    meta: &Uf2Metadata,
+) -> anyhow::Result<Vec<u8>> {
+    let num_blocks = segments
+        .iter()
+        .map(|(_, data)| data.len().div_ceil(UF2_PAYLOAD_SIZE))
+        .sum::<usize>()
+        .max(1) as u32;
+    let tags = if meta.is_empty() {
+        Vec::new()
+    } else {
+        meta.encode()?
+    };
+
+    let mut out = Vec::with_capacity(num_blocks as usize * 512);
+    let mut block_no = 0u32;
+    for (p_paddr, data) in segments {
+        let seg_base = base_addr + (p_paddr - min_lma);
+        for start in (0..data.len()).step_by(UF2_PAYLOAD_SIZE) {
+            let end = min(start + UF2_PAYLOAD_SIZE, data.len());
+            let chunk = &data[start..end];
+            let tags = if block_no == 0 { tags.as_slice() } else { &[] };
+            write_uf2_block(
+                &mut out,
+                seg_base + start as u32,
+                chunk,
+                block_no,
+                num_blocks,
+                family_id,
+                tags,
+            )?;
+            block_no += 1;
+        }
+    }
+
+    Ok(out)
+}
+